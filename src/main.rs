@@ -1,6 +1,6 @@
 use actix_files as fs;
 use actix_web::{middleware, web, App, HttpResponse, HttpServer, Responder};
-mod markdown;
+use rust_demo::markdown;
 
 async fn index() -> impl Responder {
     HttpResponse::Ok().body("Markdown to HTML demo")
@@ -12,7 +12,13 @@ async fn main() -> std::io::Result<()> {
     env_logger::init();
     HttpServer::new(|| {
         App::new()
-            .wrap(markdown::Transformer)
+            .wrap(
+                markdown::Transformer::new()
+                    .enable_tables()
+                    .enable_strikethrough()
+                    .static_root(".")
+                    .static_mount("/files"),
+            )
             .wrap(middleware::Compress::default())
             .wrap(middleware::Logger::default())
             .route("/", web::get().to(index))
@@ -1,169 +1,823 @@
-use std::future::Future;
+use std::collections::HashSet;
+use std::future::{ready, Future, Ready};
+use std::path::{Component, Path, PathBuf};
 use std::pin::Pin;
+use std::rc::Rc;
 use std::task::{Context, Poll};
 
-use actix_http::http::HeaderValue;
-use actix_service::{Service, Transform};
-use actix_web::body::{BodySize, MessageBody, ResponseBody};
-use actix_web::{dev::ServiceRequest, dev::ServiceResponse, http, Error};
+use actix_web::body::{BodySize, EitherBody, MessageBody};
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderValue, ACCEPT, ACCEPT_LANGUAGE, CONTENT_TYPE};
+use actix_web::http::{StatusCode, Uri};
+use actix_web::{dev::Transform, Error};
 use bytes::{Bytes, BytesMut};
-use futures::future::{ok, Ready};
 use pulldown_cmark::{html, Options, Parser};
 
-pub enum ConditionalResponse<A, I> {
-    Active(A),
-    Inactive(I),
+const DEFAULT_TITLE: &str = "Markdown Page";
+const DEFAULT_TEMPLATE: &str =
+    "<!DOCTYPE html><html><head><title>{title}</title></head><body>{body}</body></html>";
+
+/// Splits a `{title}`/`{body}` template around its `{body}` placeholder,
+/// substituting `{title}` up front since it doesn't depend on the stream.
+fn split_template(template: &str, title: &str) -> (Rc<str>, Rc<str>) {
+    let resolved = template.replace("{title}", title);
+    match resolved.split_once("{body}") {
+        Some((prelude, postlude)) => (Rc::from(prelude), Rc::from(postlude)),
+        None => (Rc::from(resolved.as_str()), Rc::from("")),
+    }
+}
+
+/// Checks whether `bytes` is valid UTF-8 so far, mid-stream: an incomplete
+/// multi-byte sequence at the very end is tolerated, since more bytes may
+/// still arrive to complete it. Returns `false` only once invalid bytes are
+/// unambiguously present. Not suitable for an end-of-stream check, where a
+/// trailing incomplete sequence is no longer excusable — use plain
+/// `std::str::from_utf8` there instead.
+fn is_valid_utf8_so_far(bytes: &[u8]) -> bool {
+    match std::str::from_utf8(bytes) {
+        Ok(_) => true,
+        Err(e) => e.error_len().is_none(),
+    }
+}
+
+/// Renders one Markdown block into an HTML fragment using the given
+/// CommonMark/GFM `options`.
+fn render_block(source: &str, options: Options) -> String {
+    let parser = Parser::new_ext(source, options);
+    let mut html_output = String::with_capacity(source.len() * 3 / 2);
+    html::push_html(&mut html_output, parser);
+    html_output
+}
+
+/// True when `line` opens a bullet or ordered list item.
+fn starts_list_item(line: &str) -> bool {
+    let trimmed = line.trim_start();
+    if let Some(rest) = trimmed.strip_prefix(['-', '*', '+']) {
+        return rest.is_empty() || rest.starts_with(' ');
+    }
+    let digits = trimmed.bytes().take_while(u8::is_ascii_digit).count();
+    if digits == 0 {
+        return false;
+    }
+    match trimmed[digits..].strip_prefix(['.', ')']) {
+        Some(rest) => rest.is_empty() || rest.starts_with(' '),
+        None => false,
+    }
+}
+
+/// True when `line` continues the list item or blockquote above it, either
+/// via indentation or a repeated `>` marker.
+fn is_continuation_line(line: &str) -> bool {
+    line.starts_with(' ') || line.starts_with('\t') || line.trim_start().starts_with('>')
+}
+
+/// Splits off every complete blank-line-delimited block from `buffer`, up to
+/// the last such boundary that isn't inside an open ``` fence or a loose list
+/// (a blank line followed by another item or an indented continuation), and
+/// returns it as a `String`. A blank line is only treated as a boundary once
+/// the following line confirms the list, if any, has actually ended, so a
+/// boundary candidate right at the end of `buffer` is left unresolved until
+/// more input (or end of stream) settles it. The trailing partial block, if
+/// any, is left in `buffer`. Returns `None` until a safe boundary shows up.
+fn take_safe_block(
+    buffer: &mut BytesMut,
+    in_fence: &mut bool,
+    in_list: &mut bool,
+) -> Option<String> {
+    let valid_len = match std::str::from_utf8(buffer) {
+        Ok(text) => text.len(),
+        Err(e) => e.valid_up_to(),
+    };
+    let text = std::str::from_utf8(&buffer[..valid_len])
+        .expect("valid_len is the length of the validated UTF-8 prefix");
+
+    let mut fence = *in_fence;
+    let mut list = *in_list;
+    let mut offset = 0usize;
+    let mut boundary = None;
+    let mut pending_blank = None;
+
+    for line in text.split_inclusive('\n') {
+        let trimmed = line.trim_end_matches('\n').trim_end_matches('\r');
+        offset += line.len();
+
+        if trimmed.trim_start().starts_with("```") {
+            fence = !fence;
+            pending_blank = None;
+            continue;
+        }
+        if fence {
+            continue;
+        }
+        if trimmed.trim().is_empty() {
+            pending_blank = Some(offset);
+            continue;
+        }
+        if let Some(blank_offset) = pending_blank.take() {
+            if !list || !(starts_list_item(trimmed) || is_continuation_line(trimmed)) {
+                boundary = Some(blank_offset);
+                list = false;
+            }
+        }
+        if starts_list_item(trimmed) {
+            list = true;
+        } else if !is_continuation_line(trimmed) {
+            list = false;
+        }
+    }
+
+    let boundary = boundary?;
+    // Only the text after `boundary` stays in `buffer` and is rescanned from
+    // its start on the next call; no fence or list is open at that point.
+    *in_fence = false;
+    *in_list = false;
+    let tail = buffer.split_off(boundary);
+    let head = std::mem::replace(buffer, tail);
+    let block = String::from_utf8(head.to_vec())
+        .expect("boundary falls on a line break inside the validated UTF-8 prefix");
+    Some(block)
+}
+
+/// Middleware factory that rewrites responses of a `source` content type
+/// into a `target` content type using a pluggable `render` closure.
+/// Defaults to turning `text/markdown` into `text/html` via pulldown-cmark.
+pub struct Transformer {
+    source: String,
+    target: String,
+    options: Options,
+    render: Option<Rc<dyn Fn(&str) -> String>>,
+    template: String,
+    strict_utf8: bool,
+    error_body: Option<String>,
+    source_extension: String,
+    static_root: PathBuf,
+    static_mount: String,
 }
 
-type MarkdownResponse<B> = ConditionalResponse<MarkdownBody<B>, ResponseBody<B>>;
+impl Transformer {
+    pub fn new() -> Self {
+        Self {
+            source: "text/markdown".to_owned(),
+            target: "text/html".to_owned(),
+            options: Options::empty(),
+            render: None,
+            template: DEFAULT_TEMPLATE.to_owned(),
+            strict_utf8: false,
+            error_body: None,
+            source_extension: ".md".to_owned(),
+            static_root: PathBuf::from("."),
+            static_mount: "/".to_owned(),
+        }
+    }
+
+    /// Content type that triggers the transform. Defaults to `text/markdown`.
+    pub fn source_content_type(mut self, content_type: impl Into<String>) -> Self {
+        self.source = content_type.into();
+        self
+    }
+
+    /// Content type the response is rewritten to. Defaults to `text/html`.
+    pub fn target_content_type(mut self, content_type: impl Into<String>) -> Self {
+        self.target = content_type.into();
+        self
+    }
+
+    /// The closure used to turn a source-typed body into the target type.
+    /// Overrides the built-in pulldown-cmark renderer, so `with_options`
+    /// and the `enable_*` helpers no longer have any effect.
+    pub fn render<F>(mut self, render: F) -> Self
+    where
+        F: Fn(&str) -> String + 'static,
+    {
+        self.render = Some(Rc::new(render));
+        self
+    }
+
+    /// Sets the full set of CommonMark/GFM extensions passed to
+    /// `Parser::new_ext` by the built-in renderer, replacing whatever was
+    /// set before it (including by the `enable_*` helpers).
+    pub fn with_options(mut self, options: Options) -> Self {
+        self.options = options;
+        self
+    }
+
+    pub fn enable_tables(mut self) -> Self {
+        self.options.insert(Options::ENABLE_TABLES);
+        self
+    }
+
+    pub fn enable_footnotes(mut self) -> Self {
+        self.options.insert(Options::ENABLE_FOOTNOTES);
+        self
+    }
+
+    pub fn enable_strikethrough(mut self) -> Self {
+        self.options.insert(Options::ENABLE_STRIKETHROUGH);
+        self
+    }
+
+    pub fn enable_tasklists(mut self) -> Self {
+        self.options.insert(Options::ENABLE_TASKLISTS);
+        self
+    }
+
+    pub fn enable_smart_punctuation(mut self) -> Self {
+        self.options.insert(Options::ENABLE_SMART_PUNCTUATION);
+        self
+    }
+
+    /// Sets the HTML shell the rendered body is wrapped in. Must contain a
+    /// `{body}` placeholder; `{title}` is optional. Defaults to a bare page
+    /// titled "Markdown Page".
+    pub fn template(mut self, template: impl Into<String>) -> Self {
+        self.template = template.into();
+        self
+    }
+
+    /// Rejects non-UTF-8 source bytes instead of silently lossy-decoding
+    /// them. On failure the body stream ends with an error unless
+    /// `error_body` supplies a fallback body to render instead. The status
+    /// is set before the body is read, so it is not changed to a `500`.
+    pub fn strict_utf8(mut self, strict: bool) -> Self {
+        self.strict_utf8 = strict;
+        self
+    }
+
+    /// Fallback HTML served in place of a hard error when `strict_utf8` is
+    /// enabled and the source turns out not to be valid UTF-8.
+    pub fn error_body(mut self, body: impl Into<String>) -> Self {
+        self.error_body = Some(body.into());
+        self
+    }
+
+    /// File extension (including the leading dot) that identifies a
+    /// localizable source file, e.g. `.md` for `README.md`. Defaults to
+    /// `.md`.
+    pub fn source_extension(mut self, extension: impl Into<String>) -> Self {
+        self.source_extension = extension.into();
+        self
+    }
 
-pub struct Transformer;
+    /// Filesystem root the request path is resolved against when looking
+    /// for a localized variant, matching the directory handed to
+    /// `fs::Files`. Defaults to `.`.
+    pub fn static_root(mut self, root: impl Into<PathBuf>) -> Self {
+        self.static_root = root.into();
+        self
+    }
+
+    /// Mount path the request is served under, matching the `mount_path`
+    /// handed to `fs::Files::new`, so the lookup against `static_root`
+    /// strips the same prefix `fs::Files` strips before resolving against
+    /// its directory. Defaults to `/` (no prefix to strip).
+    pub fn static_mount(mut self, mount: impl Into<String>) -> Self {
+        self.static_mount = mount.into();
+        self
+    }
+}
+
+impl Default for Transformer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
-impl<S: 'static, B> Transform<S> for Transformer
+impl<S, B> Transform<S, ServiceRequest> for Transformer
 where
-    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
     B: MessageBody + 'static,
+    B::Error: Into<Error>,
 {
-    type Request = ServiceRequest;
-    type Response = ServiceResponse<MarkdownResponse<B>>;
+    type Response = ServiceResponse<EitherBody<MarkdownBody<B>, B>>;
     type Error = Error;
     type InitError = ();
     type Transform = MarkdownTransformerMiddleware<S>;
     type Future = Ready<Result<Self::Transform, Self::InitError>>;
 
     fn new_transform(&self, service: S) -> Self::Future {
-        ok(MarkdownTransformerMiddleware { service })
+        let options = self.options;
+        let render = self
+            .render
+            .clone()
+            .unwrap_or_else(|| Rc::new(move |source: &str| render_block(source, options)));
+        let (prelude, postlude) = split_template(&self.template, DEFAULT_TITLE);
+        ready(Ok(MarkdownTransformerMiddleware {
+            service,
+            source: self.source.clone(),
+            target: self.target.clone(),
+            render,
+            prelude,
+            postlude,
+            strict_utf8: self.strict_utf8,
+            error_body: self.error_body.clone().map(Rc::from),
+            source_extension: self.source_extension.clone(),
+            static_root: self.static_root.clone(),
+            static_mount: self.static_mount.clone(),
+        }))
     }
 }
 
 pub struct MarkdownTransformerMiddleware<S> {
     service: S,
+    source: String,
+    target: String,
+    render: Rc<dyn Fn(&str) -> String>,
+    prelude: Rc<str>,
+    postlude: Rc<str>,
+    strict_utf8: bool,
+    error_body: Option<Rc<str>>,
+    source_extension: String,
+    static_root: PathBuf,
+    static_mount: String,
+}
+
+impl<S> MarkdownTransformerMiddleware<S> {
+    /// Rewrites `README.md` to the best available localized variant
+    /// (`README.fr.md`, then `README.en.md`, ...) per `Accept-Language`.
+    ///
+    /// The existence check resolves against `static_root` using the request
+    /// path with `static_mount` stripped, mirroring how `fs::Files` resolves
+    /// the same request against its directory; the rewritten URI keeps the
+    /// full, mount-prefixed path so it still reaches that `fs::Files`.
+    fn localize_path(&self, mut req: ServiceRequest) -> ServiceRequest {
+        let path = req.path();
+        if !path.ends_with(&self.source_extension) || !is_safe_static_path(path) {
+            return req;
+        }
+        let Some(relative_path) = strip_mount(path, &self.static_mount) else {
+            return req;
+        };
+
+        let languages = req
+            .headers()
+            .get(ACCEPT_LANGUAGE)
+            .and_then(|header| header.to_str().ok())
+            .map(parse_accept_language)
+            .unwrap_or_default();
+
+        let stem = path[..path.len() - self.source_extension.len()].to_owned();
+        let relative_stem =
+            relative_path[..relative_path.len() - self.source_extension.len()].to_owned();
+        let mut tried = HashSet::new();
+
+        for (tag, _) in &languages {
+            if tag == "*" {
+                break;
+            }
+            for candidate_tag in [tag.as_str(), tag.split('-').next().unwrap_or(tag)] {
+                if !is_safe_language_tag(candidate_tag) || !tried.insert(candidate_tag.to_owned()) {
+                    continue;
+                }
+                let relative_candidate = format!(
+                    "{}.{}{}",
+                    relative_stem, candidate_tag, self.source_extension
+                );
+                if self.static_root.join(&relative_candidate).is_file() {
+                    let candidate = format!("{}.{}{}", stem, candidate_tag, self.source_extension);
+                    rewrite_path(&mut req, &candidate);
+                    return req;
+                }
+            }
+        }
+        req
+    }
+}
+
+/// Whether `tag` is safe to splice into a filesystem path: a client-supplied
+/// `Accept-Language` tag must not be allowed to escape `static_root` via
+/// path separators or `..` segments.
+fn is_safe_language_tag(tag: &str) -> bool {
+    !tag.is_empty() && !tag.contains(['/', '\\']) && tag != ".." && tag != "."
+}
+
+/// Whether `path` is safe to resolve under `static_root`: a client-supplied
+/// request path must not be allowed to escape it via `..` (or, on Windows,
+/// a drive-letter prefix) components.
+fn is_safe_static_path(path: &str) -> bool {
+    Path::new(path)
+        .components()
+        .all(|component| !matches!(component, Component::ParentDir | Component::Prefix(_)))
+}
+
+/// Strips `mount` from the front of `path`, the way `fs::Files::new(mount,
+/// directory)` strips it before resolving a request against `directory`.
+/// `mount` of `/` just strips the leading slash; any other mount must match
+/// on a `/` boundary, so `/files` doesn't also swallow `/files2/...`.
+fn strip_mount<'a>(path: &'a str, mount: &str) -> Option<&'a str> {
+    if mount == "/" {
+        return Some(path.trim_start_matches('/'));
+    }
+    let rest = path.strip_prefix(mount)?;
+    if rest.is_empty() || rest.starts_with('/') {
+        Some(rest.trim_start_matches('/'))
+    } else {
+        None
+    }
 }
 
-impl<S, B> Service for MarkdownTransformerMiddleware<S>
+/// Rewrites `req`'s URI to `new_path`, keeping its existing query string.
+fn rewrite_path(req: &mut ServiceRequest, new_path: &str) {
+    let head = req.head_mut();
+    let mut parts = head.uri.clone().into_parts();
+    let path_and_query = match head.uri.query() {
+        Some(query) => format!("{}?{}", new_path, query),
+        None => new_path.to_owned(),
+    };
+    if let Ok(path_and_query) = path_and_query.parse() {
+        parts.path_and_query = Some(path_and_query);
+        if let Ok(uri) = Uri::from_parts(parts) {
+            head.uri = uri;
+        }
+    }
+}
+
+/// Parses `Accept-Language` into `(tag, q)` pairs, with `q=0` entries
+/// dropped and the rest sorted highest-quality first.
+fn parse_accept_language(header: &str) -> Vec<(String, f32)> {
+    let mut tags = parse_q_values(header);
+    tags.retain(|(_, q)| *q > 0.0);
+    tags.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+    tags
+}
+
+impl<S, B> Service<ServiceRequest> for MarkdownTransformerMiddleware<S>
 where
-    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
-    B: MessageBody,
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+    B::Error: Into<Error>,
 {
-    type Request = ServiceRequest;
-    type Response = ServiceResponse<MarkdownResponse<B>>;
+    type Response = ServiceResponse<EitherBody<MarkdownBody<B>, B>>;
     type Error = Error;
     type Future = WrapperStream<S>;
 
-    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
-        self.service.poll_ready(cx)
-    }
+    actix_web::dev::forward_ready!(service);
 
-    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let accept = req
+            .headers()
+            .get(ACCEPT)
+            .and_then(|header| header.to_str().ok())
+            .map(parse_accept)
+            .unwrap_or_else(|| vec![("*/*".to_owned(), 1.0)]);
+        let req = self.localize_path(req);
         WrapperStream {
             fut: self.service.call(req),
+            accept,
+            source: self.source.clone(),
+            target: self.target.clone(),
+            render: self.render.clone(),
+            prelude: self.prelude.clone(),
+            postlude: self.postlude.clone(),
+            strict_utf8: self.strict_utf8,
+            error_body: self.error_body.clone(),
         }
     }
 }
 
-fn get_buffer_with_capacity(capacity: BodySize) -> BytesMut {
-    use BodySize::*;
-    match capacity {
-        Sized(capacity) => BytesMut::with_capacity(capacity),
-        Sized64(capacity) => BytesMut::with_capacity(capacity as usize),
-        _ => BytesMut::new(),
-    }
+/// Parses an `Accept` header into `(media_type, q)` pairs. Thin wrapper
+/// around `parse_q_values`, kept separate so callers don't need to know
+/// `Accept` and `Accept-Language` share a parser.
+fn parse_accept(header: &str) -> Vec<(String, f32)> {
+    parse_q_values(header)
+}
+
+/// Parses the `token[;q=value]`-list format shared by `Accept` and
+/// `Accept-Language`: comma-separated, lower-cased tokens with `q`
+/// clamped to `[0, 1]` (defaulting to `1.0` when absent).
+fn parse_q_values(header: &str) -> Vec<(String, f32)> {
+    header
+        .split(',')
+        .filter_map(|entry| {
+            let mut parts = entry.split(';');
+            let token = parts.next()?.trim().to_lowercase();
+            if token.is_empty() {
+                return None;
+            }
+            let q: f32 = parts
+                .filter_map(|param| {
+                    let mut kv = param.splitn(2, '=');
+                    let key = kv.next()?.trim();
+                    let value = kv.next()?.trim();
+                    key.eq_ignore_ascii_case("q").then(|| value.parse().ok())?
+                })
+                .next()
+                .unwrap_or(1.0);
+            Some((token, q.max(0.0).min(1.0)))
+        })
+        .collect()
+}
+
+/// The client's preferred quality value for `media_type`, honoring `*/*`
+/// and `type/*` wildcards. Returns `0.0` if nothing in `accept` matches.
+fn accept_quality(accept: &[(String, f32)], media_type: &str) -> f32 {
+    let type_ = media_type.split('/').next().unwrap_or(media_type);
+    accept
+        .iter()
+        .filter(|(candidate, _)| {
+            candidate == "*/*" || candidate == media_type || candidate == &format!("{}/*", type_)
+        })
+        .map(|(_, q)| *q)
+        .fold(0.0_f32, f32::max)
 }
 
 #[pin_project::pin_project]
 pub struct WrapperStream<S>
 where
-    S: Service,
+    S: Service<ServiceRequest>,
 {
     #[pin]
     fut: S::Future,
+    accept: Vec<(String, f32)>,
+    source: String,
+    target: String,
+    render: Rc<dyn Fn(&str) -> String>,
+    prelude: Rc<str>,
+    postlude: Rc<str>,
+    strict_utf8: bool,
+    error_body: Option<Rc<str>>,
 }
 
 impl<S, B> Future for WrapperStream<S>
 where
-    B: MessageBody,
-    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    B: MessageBody + 'static,
+    B::Error: Into<Error>,
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
 {
-    type Output = Result<ServiceResponse<MarkdownResponse<B>>, Error>;
+    type Output = Result<ServiceResponse<EitherBody<MarkdownBody<B>, B>>, Error>;
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        let res = futures::ready!(self.project().fut.poll(cx));
+        let this = self.project();
+        let accept = this.accept;
+        let source = this.source;
+        let target = this.target;
+        let render = this.render;
+        let prelude = this.prelude;
+        let postlude = this.postlude;
+        let strict_utf8 = *this.strict_utf8;
+        let error_body = this.error_body;
+        let res = futures::ready!(this.fut.poll(cx));
 
         Poll::Ready(res.map(|mut res| {
-            if res
+            let is_source = res
                 .headers()
-                .get("content-type")
-                .map(|header| header.eq(&HeaderValue::from_static("text/markdown")))
-                .unwrap_or(false)
-            {
-                res.headers_mut().insert(
-                    http::header::CONTENT_TYPE,
-                    HeaderValue::from_static("text/html"),
-                );
-                return res.map_body(move |_, body| {
-                    let size = body.size();
-                    ResponseBody::Body(MarkdownResponse::Active(MarkdownBody {
-                        body,
-                        buffer: get_buffer_with_capacity(size),
-                    }))
-                });
+                .get(CONTENT_TYPE)
+                .and_then(|header| header.to_str().ok())
+                .map(|content_type| content_type.eq_ignore_ascii_case(source))
+                .unwrap_or(false);
+
+            if is_source {
+                let source_q = accept_quality(accept, source);
+                let target_q = accept_quality(accept, target);
+
+                if target_q <= 0.0 && source_q <= 0.0 {
+                    *res.response_mut().status_mut() = StatusCode::NOT_ACCEPTABLE;
+                    return res.map_body(|_, body| EitherBody::right(body));
+                }
+
+                if target_q >= source_q && target_q > 0.0 {
+                    if let Ok(value) = HeaderValue::from_str(target) {
+                        res.headers_mut().insert(CONTENT_TYPE, value);
+                    }
+                    return res.map_body(|_, body| {
+                        EitherBody::left(MarkdownBody {
+                            body,
+                            buffer: BytesMut::new(),
+                            render: render.clone(),
+                            prelude: prelude.clone(),
+                            postlude: postlude.clone(),
+                            strict_utf8,
+                            error_body: error_body.clone(),
+                            started: false,
+                            in_fence: false,
+                            in_list: false,
+                            done: false,
+                        })
+                    });
+                }
             }
-            res.map_body(move |_, body| ResponseBody::Body(MarkdownResponse::Inactive(body)))
+            res.map_body(|_, body| EitherBody::right(body))
         }))
     }
 }
 
+#[pin_project::pin_project]
 pub struct MarkdownBody<B> {
-    body: ResponseBody<B>,
+    #[pin]
+    body: B,
     buffer: BytesMut,
+    render: Rc<dyn Fn(&str) -> String>,
+    prelude: Rc<str>,
+    postlude: Rc<str>,
+    strict_utf8: bool,
+    error_body: Option<Rc<str>>,
+    started: bool,
+    in_fence: bool,
+    in_list: bool,
+    done: bool,
 }
 
-impl<B: MessageBody> MarkdownBody<B> {
-    fn is_complete(&self) -> bool {
-        use BodySize::*;
-        match self.body.size() {
-            None | Empty => true,
-            Sized(size) => size <= self.buffer.len(),
-            Sized64(size) => size <= self.buffer.len() as u64,
-            _ => false,
-        }
-    }
-}
+impl<B> MessageBody for MarkdownBody<B>
+where
+    B: MessageBody,
+    B::Error: Into<Error>,
+{
+    type Error = Error;
 
-impl<B: MessageBody> MessageBody for MarkdownResponse<B> {
     fn size(&self) -> BodySize {
-        use ConditionalResponse::*;
-        match self {
-            Active(_body) => BodySize::Stream,
-            Inactive(body) => body.size(),
-        }
+        BodySize::Stream
     }
 
-    fn poll_next(&mut self, cx: &mut Context<'_>) -> Poll<Option<Result<Bytes, Error>>> {
-        use ConditionalResponse::*;
-        match self {
-            Inactive(body) => body.poll_next(cx),
-            Active(response_body) => match response_body.body.poll_next(cx) {
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        let mut this = self.project();
+        if *this.done {
+            return Poll::Ready(None);
+        }
+        loop {
+            match this.body.as_mut().poll_next(cx) {
                 Poll::Ready(Some(Ok(chunk))) => {
-                    response_body.buffer.extend_from_slice(&chunk);
-                    if !response_body.is_complete() {
-                        cx.waker().clone().wake();
-                        return Poll::Pending;
+                    this.buffer.extend_from_slice(&chunk);
+                    if *this.strict_utf8 && !is_valid_utf8_so_far(this.buffer) {
+                        let started = *this.started;
+                        *this.done = true;
+                        *this.started = true;
+                        return Poll::Ready(Some(decode_error(
+                            this.error_body,
+                            this.prelude,
+                            this.postlude,
+                            started,
+                        )));
+                    }
+                    if let Some(block) = take_safe_block(this.buffer, this.in_fence, this.in_list) {
+                        let mut output = String::new();
+                        if !*this.started {
+                            output.push_str(this.prelude);
+                            *this.started = true;
+                        }
+                        output.push_str(&(this.render)(&block));
+                        return Poll::Ready(Some(Ok(Bytes::from(output))));
                     }
-                    let s = &String::from_utf8_lossy(&response_body.buffer);
-                    let parser = Parser::new_ext(s, Options::empty());
-                    let mut html_output: String = String::with_capacity(s.len() * 3 / 2);
-                    html_output.push_str(
-                        "<!DOCTYPE html><html><head><title>Markdown Page</title></head><body>",
-                    );
-                    html::push_html(&mut html_output, parser);
-                    html_output.push_str("</body></html>");
-                    Poll::Ready(Some(Ok(Bytes::from(html_output))))
                 }
-                Poll::Ready(Some(Err(e))) => Poll::Ready(Some(Err(e))),
-                Poll::Ready(None) => Poll::Ready(None),
-                Poll::Pending => Poll::Pending,
-            },
+                Poll::Ready(Some(Err(e))) => {
+                    *this.done = true;
+                    return Poll::Ready(Some(Err(e.into())));
+                }
+                Poll::Ready(None) => {
+                    *this.done = true;
+                    if *this.strict_utf8 && std::str::from_utf8(this.buffer).is_err() {
+                        let started = *this.started;
+                        *this.started = true;
+                        return Poll::Ready(Some(decode_error(
+                            this.error_body,
+                            this.prelude,
+                            this.postlude,
+                            started,
+                        )));
+                    }
+                    let mut output = String::new();
+                    if !*this.started {
+                        output.push_str(this.prelude);
+                    }
+                    if !this.buffer.is_empty() {
+                        let remaining = String::from_utf8_lossy(this.buffer).into_owned();
+                        output.push_str(&(this.render)(&remaining));
+                        this.buffer.clear();
+                    }
+                    output.push_str(this.postlude);
+                    return Poll::Ready(Some(Ok(Bytes::from(output))));
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+/// Produces the result to yield when strict UTF-8 validation fails: the
+/// caller-supplied fallback body, wrapped in `prelude`/`postlude` like a
+/// normal rendered document (skipping `prelude` if it was already sent), if
+/// a fallback was configured; otherwise an internal-server-error `Error`
+/// that ends the body stream. The response status, already set when the
+/// body is polled, stays as it was.
+fn decode_error(
+    error_body: &Option<Rc<str>>,
+    prelude: &str,
+    postlude: &str,
+    started: bool,
+) -> Result<Bytes, Error> {
+    match error_body {
+        Some(body) => {
+            let mut output = String::new();
+            if !started {
+                output.push_str(prelude);
+            }
+            output.push_str(body);
+            output.push_str(postlude);
+            Ok(Bytes::from(output))
         }
+        None => Err(actix_web::error::ErrorInternalServerError(
+            "invalid UTF-8 in markdown source",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn take_safe_block_keeps_loose_list_together() {
+        let mut fence = false;
+        let mut list = false;
+        let mut buffer = BytesMut::from("- one\n\n- two\n\nnext paragraph\n".as_bytes());
+        let block = take_safe_block(&mut buffer, &mut fence, &mut list)
+            .expect("boundary after the paragraph closes the list");
+        assert_eq!(block, "- one\n\n- two\n\n");
+        assert_eq!(&buffer[..], b"next paragraph\n");
+    }
+
+    #[test]
+    fn take_safe_block_keeps_fenced_block_together() {
+        let mut fence = false;
+        let mut list = false;
+        let mut buffer =
+            BytesMut::from("```\nfn main() {}\n\nstill inside\n```\n\nafter\n".as_bytes());
+        let block = take_safe_block(&mut buffer, &mut fence, &mut list)
+            .expect("boundary after the closed fence");
+        assert_eq!(block, "```\nfn main() {}\n\nstill inside\n```\n\n");
+        assert_eq!(&buffer[..], b"after\n");
+    }
+
+    #[test]
+    fn eof_check_rejects_a_multibyte_char_truncated_at_the_very_end() {
+        let buffer = BytesMut::from(&[b'a', 0xC2][..]);
+        assert!(
+            is_valid_utf8_so_far(&buffer),
+            "mid-stream: 0xC2 could still be completed by the next chunk"
+        );
+        assert!(
+            std::str::from_utf8(&buffer).is_err(),
+            "at EOF no more bytes are coming, so the same trailing byte is fatal"
+        );
+    }
+
+    fn test_middleware(
+        static_root: PathBuf,
+        static_mount: &str,
+    ) -> MarkdownTransformerMiddleware<()> {
+        MarkdownTransformerMiddleware {
+            service: (),
+            source: "text/markdown".to_owned(),
+            target: "text/html".to_owned(),
+            render: Rc::new(|s: &str| s.to_owned()),
+            prelude: Rc::from(""),
+            postlude: Rc::from(""),
+            strict_utf8: false,
+            error_body: None,
+            source_extension: ".md".to_owned(),
+            static_root,
+            static_mount: static_mount.to_owned(),
+        }
+    }
+
+    #[test]
+    fn localize_path_resolves_under_a_files_mount_prefix() {
+        let dir =
+            std::env::temp_dir().join(format!("rust-demo-markdown-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("README.fr.md"), "bonjour").unwrap();
+
+        let middleware = test_middleware(dir.clone(), "/files");
+        let req = TestRequest::get()
+            .uri("/files/README.md")
+            .insert_header((ACCEPT_LANGUAGE, "fr"))
+            .to_srv_request();
+        let req = middleware.localize_path(req);
+        assert_eq!(req.path(), "/files/README.fr.md");
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn localize_path_rejects_path_traversal_in_the_request_path() {
+        let middleware = test_middleware(PathBuf::from("."), "/");
+        let req = TestRequest::get()
+            .uri("/../../etc/passwd.md")
+            .insert_header((ACCEPT_LANGUAGE, "en"))
+            .to_srv_request();
+        let original_path = req.path().to_owned();
+        let req = middleware.localize_path(req);
+        assert_eq!(req.path(), original_path);
+    }
+
+    #[test]
+    fn parse_accept_language_drops_zero_quality_and_sorts_by_quality() {
+        let tags = parse_accept_language("fr;q=0, en-US;q=0.8, en;q=0.9");
+        assert_eq!(
+            tags,
+            vec![("en".to_owned(), 0.9), ("en-us".to_owned(), 0.8)]
+        );
     }
 }